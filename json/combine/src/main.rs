@@ -6,28 +6,35 @@ extern crate bencher;
 #[macro_use]
 extern crate combine;
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::io::Read;
 use std::fs::File;
 use std::path::Path;
+use std::rc::Rc;
 
 use bencher::{black_box, Bencher};
 
 use combine::stream::buffered::BufferedStream;
-use combine::{Parser, Stream, StreamOnce};
+use combine::{Parser, RangeStream, Stream, StreamOnce};
 use combine::error::{Consumed, ParseError};
+use combine::easy;
 
-use combine::parser::char::{char, digit, spaces, string};
+use combine::parser::char::{char, digit, newline, spaces, string};
+use combine::parser::combinator::look_ahead;
 use combine::parser::item::{any, satisfy, satisfy_map};
+use combine::parser::range::take_while1;
+use combine::parser::repeat::count_min_max;
 use combine::parser::sequence::between;
 use combine::parser::repeat::{many, sep_by, many1};
 use combine::parser::choice::{choice, optional};
 use combine::parser::function::parser;
 
-use combine::stream::IteratorStream;
+use combine::stream::{IteratorStream, PointerOffset};
 use combine::stream::state::{SourcePosition, State};
 
-//FIXME: return a &str instead of a string for String element and object keys
+// Owned strings/keys; `BorrowedValue` below is the zero-copy fast path that `parse_borrowed` benchmarks against this one.
 #[derive(PartialEq, Debug)]
 enum Value {
     Number(f64),
@@ -159,20 +166,71 @@ where
 
 // We need to use `parser!` to break the recursive use of `value` to prevent the returned parser
 // from containing itself
+//
+// Peeks the next non-space character once and routes directly to the
+// matching parser instead of trying each `choice` alternative in order.
+// `json_value_choice_` below keeps the old body for comparison.
 parser!{
     #[inline(always)]
     fn json_value_[I]()(I) -> Value
         where [ I: Stream<Item = char> ]
+    {
+        parser(|input: &mut I| {
+            let (c, _) = try!(look_ahead(any()).parse_lazy(input).into());
+            match c {
+                '"' => json_string().map(Value::String).parse_stream(input),
+                '{' => object().parse_stream(input),
+                '[' => between(
+                    lex(char('[')),
+                    lex(char(']')),
+                    sep_by(json_value_(), lex(char(','))),
+                ).map(Value::Array)
+                    .parse_stream(input),
+                't' => lex(string("true")).map(|_| Value::Bool(true)).parse_stream(input),
+                'f' => lex(string("false")).map(|_| Value::Bool(false)).parse_stream(input),
+                'n' => lex(string("null")).map(|_| Value::Null).parse_stream(input),
+                '-' | '0' ... '9' => number().map(Value::Number).parse_stream(input),
+                _ => Err(Consumed::Empty(I::Error::empty(input.position()).into())),
+            }
+        }).expected("value")
+    }
+}
+
+fn object_choice<I>() -> impl Parser<Input = I, Output = Value>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let field = (json_string(), lex(char(':')), json_value_choice_()).map(|t| (t.0, t.2));
+    let fields = sep_by(field, lex(char(',')));
+    between(lex(char('{')), lex(char('}')), fields)
+        .map(Value::Object)
+        .expected("object")
+}
+
+#[inline(always)]
+fn json_value_choice<I>() -> impl Parser<Input = I, Output = Value>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    spaces().with(json_value_choice_())
+}
+
+parser!{
+    #[inline(always)]
+    fn json_value_choice_[I]()(I) -> Value
+        where [ I: Stream<Item = char> ]
     {
         let array = between(
             lex(char('[')),
             lex(char(']')),
-            sep_by(json_value_(), lex(char(','))),
+            sep_by(json_value_choice_(), lex(char(','))),
         ).map(Value::Array);
 
         choice((
             json_string().map(Value::String),
-            object(),
+            object_choice(),
             array,
             number().map(Value::Number),
             lex(string("false").map(|_| Value::Bool(false))),
@@ -223,6 +281,278 @@ fn json_test() {
     }
 }
 
+// Like `Value`, but strings borrow out of the input buffer where possible.
+#[derive(PartialEq, Debug)]
+enum BorrowedValue<'a> {
+    Number(f64),
+    String(Cow<'a, str>),
+    Bool(bool),
+    Null,
+    Object(HashMap<Cow<'a, str>, BorrowedValue<'a>>),
+    Array(Vec<BorrowedValue<'a>>),
+}
+
+enum StringPart<'a> {
+    Raw(&'a str),
+    Escaped(char),
+}
+
+fn hex_digit<I>() -> impl Parser<Input = I, Output = char>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    satisfy(|c: char| c.is_digit(16))
+}
+
+fn hex4<I>() -> impl Parser<Input = I, Output = u32>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    count_min_max(4, 4, hex_digit())
+        .map(|digits: String| u32::from_str_radix(&digits, 16).unwrap_or(0))
+}
+
+// Decodes the character(s) following a backslash, including `\uXXXX` and
+// surrogate pairs (`\uD800`-`\uDBFF` followed by `\uDC00`-`\uDFFF`).
+fn json_escape<I>() -> impl Parser<Input = I, Output = char>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    char('\\').with(parser(|input: &mut I| {
+        let (c, consumed) = try!(any().parse_lazy(input).into());
+        match c {
+            '"' => Ok(('"', consumed)),
+            '\\' => Ok(('\\', consumed)),
+            '/' => Ok(('/', consumed)),
+            'b' => Ok(('\u{0008}', consumed)),
+            'f' => Ok(('\u{000c}', consumed)),
+            'n' => Ok(('\n', consumed)),
+            'r' => Ok(('\r', consumed)),
+            't' => Ok(('\t', consumed)),
+            'u' => {
+                let (high, consumed) = try!(consumed.combine(|_| hex4().parse_stream(input)));
+                if high >= 0xD800 && high < 0xDC00 {
+                    let (low, consumed) = try!(consumed.combine(|_| {
+                        (char('\\'), char('u'), hex4())
+                            .map(|(_, _, low)| low)
+                            .parse_stream(input)
+                    }));
+                    let code = 0x10000 + (high - 0xD800) * 0x400 + (low.saturating_sub(0xDC00));
+                    Ok((::std::char::from_u32(code).unwrap_or('\u{FFFD}'), consumed))
+                } else {
+                    Ok((::std::char::from_u32(high).unwrap_or('\u{FFFD}'), consumed))
+                }
+            }
+            _ => Err(Consumed::Empty(I::Error::empty(input.position()).into())),
+        }
+    }))
+}
+
+// Returns a borrowed slice of the input when the string is a single run with
+// no escapes, falling back to an owned `String` otherwise (this includes the
+// empty string, which has no run to borrow).
+fn json_string_borrowed<'a, I>() -> impl Parser<Input = I, Output = Cow<'a, str>>
+where
+    I: RangeStream<Item = char, Range = &'a str>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let raw = take_while1(|c: char| c != '"' && c != '\\').map(StringPart::Raw);
+    let escaped = json_escape().map(StringPart::Escaped);
+
+    between(char('"'), lex(char('"')), many(raw.or(escaped))).map(
+        |mut parts: Vec<StringPart<'a>>| {
+            if parts.len() == 1 {
+                if let StringPart::Raw(s) = parts[0] {
+                    return Cow::Borrowed(s);
+                }
+            }
+
+            let mut owned = String::new();
+            for part in parts.drain(..) {
+                match part {
+                    StringPart::Raw(s) => owned.push_str(s),
+                    StringPart::Escaped(c) => owned.push(c),
+                }
+            }
+            Cow::Owned(owned)
+        },
+    ).expected("string")
+}
+
+#[test]
+fn json_string_borrowed_test() {
+    let surrogate_pair = "\"\\uD83D\\uDE00\"";
+    let cases: Vec<(&str, Cow<str>)> = vec![
+        (r#""no escapes here""#, Cow::Borrowed("no escapes here")),
+        (r#""a\tb""#, Cow::Owned("a\tb".to_string())),
+        (surrogate_pair, Cow::Owned("\u{1F600}".to_string())),
+    ];
+
+    for (input, expected) in cases {
+        match json_string_borrowed().easy_parse(input) {
+            Ok(result) => assert_eq!(result, (expected, "")),
+            Err(e) => {
+                println!("{}", e);
+                assert!(false);
+            }
+        }
+    }
+}
+
+fn object_borrowed<'a, I>() -> impl Parser<Input = I, Output = BorrowedValue<'a>>
+where
+    I: RangeStream<Item = char, Range = &'a str>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let field = (json_string_borrowed(), lex(char(':')), json_value_borrowed_())
+        .map(|t| (t.0, t.2));
+    let fields = sep_by(field, lex(char(',')));
+    between(lex(char('{')), lex(char('}')), fields)
+        .map(BorrowedValue::Object)
+        .expected("object")
+}
+
+#[inline(always)]
+fn json_value_borrowed<'a, I>() -> impl Parser<Input = I, Output = BorrowedValue<'a>>
+where
+    I: RangeStream<Item = char, Range = &'a str>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    spaces().with(json_value_borrowed_())
+}
+
+parser!{
+    #[inline(always)]
+    fn json_value_borrowed_['a, I]()(I) -> BorrowedValue<'a>
+        where [ I: RangeStream<Item = char, Range = &'a str> ]
+    {
+        let array = between(
+            lex(char('[')),
+            lex(char(']')),
+            sep_by(json_value_borrowed_(), lex(char(','))),
+        ).map(BorrowedValue::Array);
+
+        choice((
+            json_string_borrowed().map(BorrowedValue::String),
+            object_borrowed(),
+            array,
+            number().map(BorrowedValue::Number),
+            lex(string("false").map(|_| BorrowedValue::Bool(false))),
+            lex(string("true").map(|_| BorrowedValue::Bool(true))),
+            lex(string("null").map(|_| BorrowedValue::Null)),
+        ))
+    }
+}
+
+fn between_spaces<P>(p: P) -> impl Parser<Input = P::Input, Output = P::Output>
+where
+    P: Parser,
+    P::Input: Stream<Item = char>,
+    <P::Input as StreamOnce>::Error: ParseError<
+        <P::Input as StreamOnce>::Item,
+        <P::Input as StreamOnce>::Range,
+        <P::Input as StreamOnce>::Position,
+    >,
+{
+    spaces().with(p).skip(spaces())
+}
+
+// Newline must be tried first: `spaces()` inside `between_spaces` also matches `\n`, so a blank line would otherwise be swallowed as leading whitespace before the next record.
+parser!{
+    #[inline(always)]
+    fn ndjson_record[I]()(I) -> Option<Value>
+        where [ I: Stream<Item = char> ]
+    {
+        newline().map(|_| None)
+            .or(between_spaces(json_value_()).skip(newline()).map(Some))
+    }
+}
+
+fn is_incomplete<Item, Range, Position>(error: &easy::Errors<Item, Range, Position>) -> bool
+where
+    Item: PartialEq,
+    Range: PartialEq,
+{
+    error.errors.iter().any(|e| *e == easy::Error::end_of_input())
+}
+
+// Feeds characters from a queue that chunks are pushed onto, letting `BufferedStream` retry a record once more chunks arrive instead of re-parsing from byte 0.
+struct ChunkFeed {
+    queue: Rc<RefCell<VecDeque<char>>>,
+}
+
+impl Iterator for ChunkFeed {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        self.queue.borrow_mut().pop_front()
+    }
+}
+
+type NdjsonCursor = BufferedStream<IteratorStream<ChunkFeed>>;
+
+// Drives `ndjson_record` over a sequence of chunks, any of which may end in the middle of a
+// record; `cursor` only advances on a successful parse, so its final position tells us whether
+// every fed character actually landed in a completed record (anything short of that means the
+// last chunk ended mid-record with no more input coming, which is a genuine truncation error).
+fn parse_ndjson_chunked<'a, C>(chunks: C) -> Vec<Option<Value>>
+where
+    C: IntoIterator<Item = &'a str>,
+{
+    let queue = Rc::new(RefCell::new(VecDeque::new()));
+    let mut cursor: NdjsonCursor =
+        BufferedStream::new(IteratorStream::new(ChunkFeed { queue: queue.clone() }), 1);
+    let mut parser = ndjson_record();
+    let mut records = Vec::new();
+    let mut total_fed = 0;
+
+    for chunk in chunks {
+        queue.borrow_mut().extend(chunk.chars());
+        total_fed += chunk.chars().count();
+
+        loop {
+            match parser.easy_parse(cursor.clone()) {
+                Ok((value, remaining)) => {
+                    records.push(value);
+                    cursor = remaining;
+                }
+                Err(ref err) if is_incomplete(err) => break,
+                Err(err) => panic!("ndjson parse error: {}", err),
+            }
+        }
+    }
+
+    assert_eq!(cursor.position(), total_fed, "trailing unterminated record");
+
+    records
+}
+
+#[test]
+fn ndjson_test() {
+    let input = "{\"a\":1}\n\n{\"b\":2}\n";
+    let mid = input.len() / 2;
+    let chunks = vec![&input[..mid], &input[mid..]];
+
+    let mut a = HashMap::new();
+    a.insert("a".to_string(), Value::Number(1.0));
+    let mut b = HashMap::new();
+    b.insert("b".to_string(), Value::Number(2.0));
+
+    assert_eq!(
+        parse_ndjson_chunked(chunks),
+        vec![Some(Value::Object(a)), None, Some(Value::Object(b))]
+    );
+}
+
+#[test]
+#[should_panic]
+fn ndjson_truncated_record_test() {
+    parse_ndjson_chunked(vec!["{\"a\":1}"]);
+}
+
 fn parse(b: &mut Bencher, buffer: &str) {
     let mut parser = json_value();
     b.iter(|| {
@@ -233,6 +563,154 @@ fn parse(b: &mut Bencher, buffer: &str) {
     });
 }
 
+fn parse_borrowed(b: &mut Bencher, buffer: &str) {
+    let mut parser = json_value_borrowed();
+    b.iter(|| {
+        let buf = black_box(buffer);
+
+        let result = parser.easy_parse(State::new(buf)).unwrap();
+        black_box(result)
+    });
+}
+
+fn data_borrowed(b: &mut Bencher) {
+    let data = include_str!("../../data.json");
+    b.bytes = data.len() as u64;
+    parse_borrowed(b, data)
+}
+
+fn canada_borrowed(b: &mut Bencher) {
+    let data = include_str!("../../canada.json");
+    b.bytes = data.len() as u64;
+    parse_borrowed(b, data)
+}
+
+// Wraps `State<&str, SourcePosition>` but reports failures as `()`, discarding
+// all positional and "expected" detail.
+#[derive(Clone)]
+struct UnitErrorStream<'a>(State<&'a str, SourcePosition>);
+
+impl<'a> StreamOnce for UnitErrorStream<'a> {
+    type Item = char;
+    type Range = &'a str;
+    type Position = SourcePosition;
+    type Error = ();
+
+    fn uncons(&mut self) -> Result<char, ()> {
+        self.0.uncons().map_err(|_| ())
+    }
+
+    fn position(&self) -> SourcePosition {
+        self.0.position()
+    }
+}
+
+impl<'a> Stream for UnitErrorStream<'a> {}
+
+fn parse_unit(b: &mut Bencher, buffer: &str) {
+    let mut parser = json_value();
+    b.iter(|| {
+        let buf = black_box(buffer);
+
+        let result = parser.parse(UnitErrorStream(State::new(buf))).unwrap();
+        black_box(result)
+    });
+}
+
+fn parse_default(b: &mut Bencher, buffer: &str) {
+    let mut parser = json_value();
+    b.iter(|| {
+        let buf = black_box(buffer);
+
+        let result = parser.parse(State::new(buf)).unwrap();
+        black_box(result)
+    });
+}
+
+fn data_unit(b: &mut Bencher) {
+    let data = include_str!("../../data.json");
+    b.bytes = data.len() as u64;
+    parse_unit(b, data)
+}
+
+fn data_default(b: &mut Bencher) {
+    let data = include_str!("../../data.json");
+    b.bytes = data.len() as u64;
+    parse_default(b, data)
+}
+
+fn data_verbose(b: &mut Bencher) {
+    let data = include_str!("../../data.json");
+    b.bytes = data.len() as u64;
+    parse(b, data)
+}
+
+fn parse_choice(b: &mut Bencher, buffer: &str) {
+    let mut parser = json_value_choice();
+    b.iter(|| {
+        let buf = black_box(buffer);
+
+        let result = parser.easy_parse(State::new(buf)).unwrap();
+        black_box(result)
+    });
+}
+
+fn data_choice(b: &mut Bencher) {
+    let data = include_str!("../../data.json");
+    b.bytes = data.len() as u64;
+    parse_choice(b, data)
+}
+
+fn canada_choice(b: &mut Bencher) {
+    let data = include_str!("../../canada.json");
+    b.bytes = data.len() as u64;
+    parse_choice(b, data)
+}
+
+// Translates a raw pointer offset back into a line/column `SourcePosition`,
+// on demand rather than on every token.
+fn translate_position(input: &str, offset: PointerOffset) -> SourcePosition {
+    let index = offset.translate_position(input);
+    let mut position = SourcePosition { line: 1, column: 1 };
+    for c in input[..index].chars() {
+        if c == '\n' {
+            position.line += 1;
+            position.column = 1;
+        } else {
+            position.column += 1;
+        }
+    }
+    position
+}
+
+// Parses directly over the `&str` slice instead of a `State` wrapper.
+fn parse_offset(b: &mut Bencher, buffer: &str) {
+    let mut parser = json_value();
+    b.iter(|| {
+        let buf = black_box(buffer);
+
+        match parser.easy_parse(buf) {
+            Ok((result, _rest)) => black_box(result),
+            Err(err) => {
+                let position = translate_position(buf, err.position);
+                panic!("parse error at {:?}: {}", position, err);
+            }
+        }
+    });
+}
+
+fn data_offset(b: &mut Bencher) {
+    let data = include_str!("../../data.json");
+    b.bytes = data.len() as u64;
+    parse_offset(b, data)
+}
+
+fn canada_offset(b: &mut Bencher) {
+    let data = include_str!("../../canada.json");
+    b.bytes = data.len() as u64;
+    parse_offset(b, data)
+}
+
 fn basic(b: &mut Bencher) {
     let data = "  { \"a\"\t: 42,
   \"b\": [ \"x\", \"y\", 12 ] ,
@@ -276,10 +754,48 @@ fn apache(b: &mut Bencher) {
     parse(b, data)
 }
 
+// Splits `data` into chunks of at most `size` bytes, never inside a UTF-8
+// character, so a record can straddle more than one chunk but a character
+// never does.
+fn chunk_str(data: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let mut end = (start + size).min(data.len());
+        while !data.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&data[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+fn ndjson(b: &mut Bencher) {
+    let data = include_str!("../../ndjson.json");
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let chunks = chunk_str(black_box(data), 64);
+        black_box(parse_ndjson_chunked(chunks))
+    })
+}
+
 //deactivating the "basic" benchmark because the parser fails on this one
 //benchmark_group!(json, basic, data, apache, canada);
 benchmark_group!(json, basic, data, apache, canada);
-benchmark_main!(json);
+benchmark_group!(json_borrowed, data_borrowed, canada_borrowed);
+benchmark_group!(error_matrix, data_unit, data_default, data_verbose);
+benchmark_group!(dispatch_vs_choice, data_choice, canada_choice);
+benchmark_group!(offset_vs_state, data_offset, canada_offset);
+benchmark_group!(ndjson_group, ndjson);
+benchmark_main!(
+    json,
+    json_borrowed,
+    error_matrix,
+    dispatch_vs_choice,
+    offset_vs_state,
+    ndjson_group
+);
 
 /*
 fn main() {